@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+
+use ::parser::ast::Operator;
+use super::cfg::CFG;
+use super::cfg::inst::{Instruction, BinOpStruct, CallStruct, ReturnStruct,
+    BranchStruct, AssignStruct};
+use super::cfg::operand::Operand;
+use super::scope::ScopeId;
+
+// `Operand` can't be a `HashMap` key itself (its `Imm` variant wraps an
+// `f64`, which is neither `Eq` nor `Hash`), so the propagation map below
+// is keyed on a `Local` register's `(ScopeId, usize)` address instead.
+type RegKey = (ScopeId, usize);
+
+fn reg_key(operand: &Operand) -> Option<RegKey> {
+    match *operand {
+        Operand::Local(scope, offset) => Some((scope, offset)),
+        _ => None
+    }
+}
+
+/// Constant-folds and algebraically simplifies a `Function`'s CFG, tiered by
+/// `-O` level: 1 folds immediate `BinOp`s, 2 also simplifies identities
+/// (`x + 0`, `x * 1`, `x * 0`) and drops the dead `BinOp`, 3 also propagates
+/// an immediate across a block boundary when the successor has a single
+/// predecessor. Idempotent: a second pass over already-folded code is a
+/// no-op.
+pub fn fold_constants(cfg: &mut CFG, level: u8) {
+    if level == 0 {
+        return
+    }
+
+    let blocks = cfg.block_names();
+    let mut block_values: HashMap<String, HashMap<RegKey, Operand>> = HashMap::new();
+    let mut pending: HashSet<String> = blocks.iter().cloned().collect();
+
+    // Level 3's propagation needs a predecessor's folded values before its
+    // successor can use them, and nothing guarantees `blocks` puts a
+    // predecessor before its successors. So this runs as a
+    // worklist instead of a single pass: a block folds once every single
+    // predecessor it could propagate from already has, looping until
+    // nothing is left pending.
+    while !pending.is_empty() {
+        let mut progressed = false;
+
+        for block in blocks.iter() {
+            if !pending.contains(block) {
+                continue
+            }
+
+            if level == 3 {
+                let preds = cfg.predecessors(block);
+                // A back edge to `block` itself can never resolve before
+                // `block` does, so don't wait on it.
+                if preds.len() == 1 && preds[0] != *block && pending.contains(&preds[0]) {
+                    continue
+                }
+            }
+
+            let mut values = HashMap::new();
+            if level == 3 {
+                let preds = cfg.predecessors(block);
+                if preds.len() == 1 {
+                    if let Some(pred_values) = block_values.get(&preds[0]) {
+                        values = pred_values.clone();
+                    }
+                }
+            }
+
+            let mut folded = Vec::new();
+            for inst in cfg.instructions(block) {
+                if let Some(inst) = fold_instruction(inst, &mut values, level) {
+                    folded.push(inst);
+                }
+            }
+            cfg.set_instructions(block, folded);
+            block_values.insert(block.clone(), values);
+            pending.remove(block);
+            progressed = true;
+        }
+
+        if !progressed {
+            // Every remaining block is waiting on a predecessor that's also
+            // waiting (a cycle of single-predecessor blocks, which this
+            // compiler's generated CFGs shouldn't produce) -- fold what's
+            // left with no propagated values rather than looping forever.
+            for block in pending.drain() {
+                let mut values = HashMap::new();
+                let mut folded = Vec::new();
+                for inst in cfg.instructions(&block) {
+                    if let Some(inst) = fold_instruction(inst, &mut values, level) {
+                        folded.push(inst);
+                    }
+                }
+                cfg.set_instructions(&block, folded);
+                block_values.insert(block, values);
+            }
+        }
+    }
+}
+
+/// Folds, simplifies, or passes through a single instruction, recording any
+/// newly-known constant into `values` as a side effect. Returns `None` when
+/// the instruction becomes dead (its result was folded to an immediate or
+/// a no-op identity) so the caller can drop it.
+fn fold_instruction(inst: Instruction, values: &mut HashMap<RegKey, Operand>,
+    level: u8) -> Option<Instruction> {
+    let bin = match inst {
+        Instruction::BinOp(bin) => bin,
+        Instruction::Call(call) => return Some(Instruction::Call(CallStruct {
+            args: call.args.iter().map(|arg| resolve(arg, values)).collect(),
+            ..call
+        })),
+        Instruction::Return(ret) => return Some(Instruction::Return(ReturnStruct {
+            value: ret.value.as_ref().map(|v| resolve(v, values)),
+            ..ret
+        })),
+        Instruction::Branch(branch) => return Some(Instruction::Branch(BranchStruct {
+            test: branch.test.as_ref().map(|t| resolve(t, values)),
+            ..branch
+        })),
+        Instruction::Assign(assign) => return Some(Instruction::Assign(AssignStruct {
+            value: resolve(&assign.value, values),
+            ..assign
+        })),
+        other => return Some(other)
+    };
+
+    let left = resolve(&bin.left, values);
+    let right = resolve(&bin.right, values);
+
+    // Level 1: pure numeric folding.
+    if let (Operand::Imm(l), Operand::Imm(r)) = (&left, &right) {
+        if let Some(result) = apply_operator(&bin.op, *l, *r) {
+            if let Some(key) = reg_key(&bin.dest) {
+                values.insert(key, Operand::Imm(result));
+            }
+            return None
+        }
+    }
+
+    // Level 2: identity simplification, independent of whether both sides
+    // are immediates.
+    if level >= 2 {
+        if let Some(result) = simplify_identity(&bin.op, &left, &right) {
+            if let Some(key) = reg_key(&bin.dest) {
+                values.insert(key, result);
+            }
+            return None
+        }
+    }
+
+    Some(Instruction::BinOp(BinOpStruct { left, right, ..bin }))
+}
+
+fn resolve(operand: &Operand, values: &HashMap<RegKey, Operand>) -> Operand {
+    reg_key(operand).and_then(|key| values.get(&key).cloned())
+        .unwrap_or_else(|| operand.clone())
+}
+
+/// Folds a binary op over two immediates when the operator is `+`, `-`, `*`,
+/// or `/` with a nonzero divisor; any other operator is left unfolded.
+fn apply_operator(op: &Operator, left: f64, right: f64) -> Option<f64> {
+    match *op {
+        Operator::Add => Some(left + right),
+        Operator::Sub => Some(left - right),
+        Operator::Mult => Some(left * right),
+        Operator::Div if right != 0.0 => Some(left / right),
+        _ => None
+    }
+}
+
+/// Simplifies `x + 0`, `0 + x`, `x * 1`, `1 * x`, `x * 0`, and `0 * x`
+/// without requiring both sides to be immediates.
+fn simplify_identity(op: &Operator, left: &Operand, right: &Operand)
+    -> Option<Operand> {
+    match (*op, left, right) {
+        (Operator::Add, &Operand::Imm(n), other) if n == 0.0 => Some(other.clone()),
+        (Operator::Add, other, &Operand::Imm(n)) if n == 0.0 => Some(other.clone()),
+        (Operator::Mult, &Operand::Imm(n), other) if n == 1.0 => Some(other.clone()),
+        (Operator::Mult, other, &Operand::Imm(n)) if n == 1.0 => Some(other.clone()),
+        (Operator::Mult, &Operand::Imm(n), _) if n == 0.0 => Some(Operand::Imm(0.0)),
+        (Operator::Mult, _, &Operand::Imm(n)) if n == 0.0 => Some(Operand::Imm(0.0)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cfg::inst::{BinOpStruct, CallStruct, ReturnStruct,
+        BranchStruct};
+
+    #[test]
+    fn folds_immediate_binop_and_rewrites_its_use() {
+        let mut cfg = CFG::new();
+        let block = cfg.entry_block.clone();
+        let dest = cfg.new_temp();
+
+        cfg.add_inst(&block, Instruction::BinOp(BinOpStruct {
+            dest: dest.clone(), op: Operator::Add,
+            left: Operand::Imm(2.0), right: Operand::Imm(3.0)
+        }));
+        cfg.add_inst(&block, Instruction::Return(
+            ReturnStruct { return_type: "void".to_string(), value: Some(dest) }));
+
+        fold_constants(&mut cfg, 1);
+
+        let insts = cfg.instructions(&block);
+        assert_eq!(insts.len(), 1);
+        match insts[0] {
+            Instruction::Return(ref ret) =>
+                assert_eq!(ret.value, Some(Operand::Imm(5.0))),
+            _ => panic!("expected the BinOp to fold away, leaving only Return")
+        }
+    }
+
+    #[test]
+    fn dropped_binop_still_resolves_in_a_call_argument() {
+        let mut cfg = CFG::new();
+        let block = cfg.entry_block.clone();
+        let dest = cfg.new_temp();
+
+        cfg.add_inst(&block, Instruction::BinOp(BinOpStruct {
+            dest: dest.clone(), op: Operator::Mult,
+            left: Operand::Imm(1.0), right: Operand::Imm(4.0)
+        }));
+        let call_dest = cfg.new_temp();
+        cfg.add_inst(&block, Instruction::Call(CallStruct::new(
+            Some(call_dest), "foo".to_string(), vec![dest])));
+
+        fold_constants(&mut cfg, 2);
+
+        let insts = cfg.instructions(&block);
+        assert_eq!(insts.len(), 1);
+        match insts[0] {
+            Instruction::Call(ref call) =>
+                assert_eq!(call.args, vec![Operand::Imm(4.0)]),
+            _ => panic!("expected the BinOp to fold away, leaving only Call")
+        }
+    }
+
+    #[test]
+    fn simplifies_identity_without_both_sides_immediate() {
+        let mut cfg = CFG::new();
+        let block = cfg.entry_block.clone();
+        let dest = cfg.new_temp();
+
+        cfg.add_inst(&block, Instruction::BinOp(BinOpStruct {
+            dest: dest.clone(), op: Operator::Mult,
+            left: Operand::Local(0, 0), right: Operand::Imm(0.0)
+        }));
+        cfg.add_inst(&block, Instruction::Return(
+            ReturnStruct { return_type: "void".to_string(), value: Some(dest) }));
+
+        fold_constants(&mut cfg, 2);
+
+        let insts = cfg.instructions(&block);
+        match insts[0] {
+            Instruction::Return(ref ret) =>
+                assert_eq!(ret.value, Some(Operand::Imm(0.0))),
+            _ => panic!("expected the BinOp to fold away, leaving only Return")
+        }
+    }
+
+    #[test]
+    fn level_three_propagates_a_folded_immediate_across_a_block_boundary() {
+        let mut cfg = CFG::new();
+        let entry = cfg.entry_block.clone();
+        let dest = cfg.new_temp();
+
+        cfg.add_inst(&entry, Instruction::BinOp(BinOpStruct {
+            dest: dest.clone(), op: Operator::Add,
+            left: Operand::Imm(2.0), right: Operand::Imm(3.0)
+        }));
+
+        let next = cfg.new_block();
+        cfg.connect_blocks(&entry, next.clone());
+        cfg.add_inst(&entry, Instruction::Branch(
+            BranchStruct::new(None, next.clone(), None)));
+        cfg.add_inst(&next, Instruction::Return(
+            ReturnStruct { return_type: "void".to_string(), value: Some(dest) }));
+
+        fold_constants(&mut cfg, 3);
+
+        // The BinOp folded away in `entry`, and its value propagated into
+        // `next` (entry's only successor, for which entry is the only
+        // predecessor) so the Return sees the immediate directly.
+        let insts = cfg.instructions(&next);
+        assert_eq!(insts.len(), 1);
+        match insts[0] {
+            Instruction::Return(ref ret) =>
+                assert_eq!(ret.value, Some(Operand::Imm(5.0))),
+            _ => panic!("expected the Return to carry the propagated immediate")
+        }
+    }
+}
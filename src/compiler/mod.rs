@@ -1,46 +1,107 @@
 pub mod cfg;
 mod arch;
+mod fold;
 mod function;
+mod module;
+mod optimize;
 mod program;
 mod types;
+mod scope;
 mod symtbl;
 mod util;
 
 use clap::ArgMatches;
 use super::parser::ast::*;
+use self::errors::CompilerError;
 use self::function::Function;
 use self::program::Program;
 use self::cfg::CFG;
 use self::cfg::inst::*;
 use self::cfg::operand::{Operand};
+use self::module::ModuleCache;
+use self::scope::{ScopeTree, ScopeId, stmt_id, expr_id};
 use self::symtbl::{SymTbl, Symbol};
 
-pub fn compile(ast: Ast, _args: &ArgMatches) -> Program {
-    /*
+/// Parses the `-O` flag into a constant-folding level: 0 means the flag was
+/// absent or unrecognized and no folding runs.
+fn optimization_level(args: &ArgMatches) -> u8 {
     match args.value_of("o").unwrap_or("") {
-        "1" => unimplemented!(),
-        "2" => unimplemented!(),
-        "3" => unimplemented!(),
-        _ => ()
-    }
-    */
-    let mut funcs = gather_funcs(&ast);
-    let main = gather_main(&ast);
-    funcs.insert(0, main);
+        "1" => 1,
+        "2" => 2,
+        "3" => 3,
+        _ => 0
+    }
+}
 
-    Program { funcs }
+/// The directory `from m import ...` resolves `m` against, mirroring
+/// `get_file_prefix`'s notion of a file's `src_root`. Falls back to the
+/// current directory when the input file isn't available or doesn't parse.
+fn src_root(args: &ArgMatches) -> String {
+    args.value_of("file")
+        .and_then(|file| util::get_file_prefix(file).ok())
+        .map(|(root, _)| root)
+        .unwrap_or_else(|| "./".to_string())
 }
 
-fn gather_funcs(ast: &Ast) -> Vec<Function> {
-    let body = match *ast {
+pub fn compile(ast: Ast, args: &ArgMatches) -> Program {
+    let level = optimization_level(args);
+    let root_dir = src_root(args);
+    let body = match ast {
         Ast::Module { ref body } => body
     };
+
+    let mut tree = ScopeTree::new();
+    let root = tree.root();
+    let mut modules = ModuleCache::new();
+    util::gather_scope(&mut tree, root, body, 0, false, &root_dir, &mut modules)
+        .expect("failed to gather module scope");
+
+    let mut funcs = gather_funcs(body, &mut tree, root, level, &root_dir,
+        &mut modules, None);
+    let main = gather_main(body, &mut tree, root, level);
+    funcs.insert(0, main);
+
+    let mut program = Program { funcs };
+
+    if level > 0 {
+        program.eliminate_dead_code();
+    }
+
+    program
+}
+
+/// Compiles every `FunctionDef` reachable from `body`, recursing into nested
+/// `FunctionDef`s and `ClassDef` method bodies so each ends up as its own
+/// `Function` in the returned list. A `ClassDef` first pushes its own child
+/// scope and gathers it with `is_class = true`, so `self.*` attributes (and
+/// `__init__`'s handling of them) attach to that scope rather than the
+/// enclosing one, and its methods' parameter scopes are children of it.
+/// `class_name` is `Some` while recursing through a `ClassDef`'s body, so its
+/// methods are qualified as `Class.method` in `Program.funcs` -- otherwise
+/// two classes that both define `__init__` would collide under the same
+/// flat `"__init__"` name.
+fn gather_funcs(body: &Vec<Statement>, tree: &mut ScopeTree, scope: ScopeId,
+    level: u8, src_root: &str, modules: &mut ModuleCache,
+    class_name: Option<&str>) -> Vec<Function> {
     let mut functions = Vec::new();
 
     for stmt in body.iter() {
         match *stmt {
-            Statement::FunctionDef { .. } => {
-                unimplemented!()
+            Statement::FunctionDef { body: ref fn_body, .. } => {
+                let (func, func_scope) = gather_func_def(stmt, tree, scope,
+                    level, src_root, modules, class_name);
+                functions.push(func);
+                // A nested `def` inside a method is a closure of that
+                // method, not a method of the class itself.
+                functions.extend(gather_funcs(fn_body, tree, func_scope, level,
+                    src_root, modules, None));
+            },
+            Statement::ClassDef { ref name, ref body, .. } => {
+                let class_scope = tree.push_scope(scope);
+                util::gather_scope(tree, class_scope, body, 0, true, src_root,
+                    modules).expect("failed to gather class scope");
+                functions.extend(gather_funcs(body, tree, class_scope, level,
+                    src_root, modules, Some(name)));
             },
             _ => ()
         }
@@ -49,10 +110,70 @@ fn gather_funcs(ast: &Ast) -> Vec<Function> {
     functions
 }
 
-fn gather_main(ast: &Ast) -> Function {
-    let body = match *ast {
-        Ast::Module { ref body } => body
+/// Compiles a single `FunctionDef` into a `Function`: pushes a scope for its
+/// parameters, gathers the body's own locals into that same scope (the same
+/// way `compile()` gathers the module body into `root` and `gather_funcs`
+/// gathers a `ClassDef` body into its class scope), lowers the body, and
+/// synthesizes a return the same way `gather_main` synthesizes its void
+/// return on fall-through. Nested defs in the body are gathered afterward so
+/// they become their own `Function`s. `class_name` qualifies the resulting
+/// `Function`'s name the same way `gather_funcs` qualifies recursion into a
+/// `ClassDef`'s body.
+fn gather_func_def(stmt: &Statement, tree: &mut ScopeTree, parent: ScopeId,
+    level: u8, src_root: &str, modules: &mut ModuleCache,
+    class_name: Option<&str>) -> (Function, ScopeId) {
+    let (name, args, body) = match *stmt {
+        Statement::FunctionDef { ref name, ref args, ref body, .. } =>
+            (name, args, body),
+        _ => unreachable!()
+    };
+    let name = match class_name {
+        Some(class_name) => format!("{}.{}", class_name, name),
+        None => name.clone()
     };
+
+    let func_scope = util::gather_func_params(tree, parent, args, 0)
+        .expect("failed to gather function parameter scope");
+    let params_len = tree.names_in(func_scope).len();
+    util::gather_scope(tree, func_scope, body, params_len, false, src_root,
+        modules).expect("failed to gather function body scope");
+
+    let mut cfg = CFG::new();
+    let mut cur_block = cfg.entry_block.clone();
+
+    for inner in body.iter() {
+        match *inner {
+            Statement::FunctionDef { .. } | Statement::ClassDef { .. } => (),
+            _ => {
+                cur_block = compile_stmt(&mut cfg, cur_block, tree,
+                    func_scope, inner);
+            }
+        }
+    }
+
+    let exit_block = cfg.exit_block.clone();
+    if cur_block != exit_block {
+        cfg.connect_blocks(&cur_block, exit_block.clone());
+        cfg.add_inst(&cur_block, Instruction::Branch(
+            BranchStruct::new(None, exit_block.clone(), None)))
+    }
+    // Add the implicit void return for fall-through; an explicit
+    // `Statement::Return` lowers straight to `Instruction::Return` instead.
+    cfg.add_inst(&exit_block, Instruction::Return(
+        ReturnStruct { return_type: "void".to_string(), value: None }));
+
+    if level > 0 {
+        fold::fold_constants(&mut cfg, level);
+    }
+
+    let func = Function { name,
+        return_type: "void".to_string(), graph: cfg };
+
+    (func, func_scope)
+}
+
+fn gather_main(body: &Vec<Statement>, tree: &mut ScopeTree, scope: ScopeId,
+    level: u8) -> Function {
     let mut cfg = CFG::new();
     let mut cur_block = cfg.entry_block.clone();
 
@@ -60,7 +181,7 @@ fn gather_main(ast: &Ast) -> Function {
         match *stmt {
             Statement::FunctionDef { .. } | Statement::ClassDef { .. } => (),
             _ => {
-                cur_block = compile_stmt(&mut cfg, cur_block, stmt);
+                cur_block = compile_stmt(&mut cfg, cur_block, tree, scope, stmt);
             }
         }
     }
@@ -74,55 +195,590 @@ fn gather_main(ast: &Ast) -> Function {
     // Add the void return to the exit block
     cfg.add_inst(&exit_block, Instruction::Return(
         ReturnStruct { return_type: "void".to_string(), value: None }));
+
+    if level > 0 {
+        fold::fold_constants(&mut cfg, level);
+    }
+
     Function { name: "main".to_string(),
         return_type: "void".to_string(), graph: cfg }
 }
 
-fn compile_stmts(cfg: &mut CFG, mut cur_block: String, stmts: &Vec<Statement>)
-    -> String {
+fn compile_stmts(cfg: &mut CFG, mut cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmts: &Vec<Statement>) -> String {
     for stmt in stmts.iter() {
-        cur_block = compile_stmt(cfg, cur_block, stmt);
+        cur_block = compile_stmt(cfg, cur_block, tree, scope, stmt);
     }
 
     cur_block
 }
 
-fn compile_stmt(cfg: &mut CFG, cur_block: String, stmt: &Statement)
-    -> String {
+fn compile_stmt(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
+    tree.set_scope_for(stmt_id(stmt), scope);
+
     match *stmt {
-        Statement::Expr { .. } => compile_stmt_expr(cfg, cur_block, stmt),
+        Statement::Expr { .. } =>
+            compile_stmt_expr(cfg, cur_block, tree, scope, stmt),
+        Statement::If { .. } =>
+            compile_stmt_if(cfg, cur_block, tree, scope, stmt),
+        Statement::While { .. } =>
+            compile_stmt_while(cfg, cur_block, tree, scope, stmt),
+        Statement::For { .. } =>
+            compile_stmt_for(cfg, cur_block, tree, scope, stmt),
+        Statement::Return { .. } =>
+            compile_stmt_return(cfg, cur_block, tree, scope, stmt),
+        Statement::Assign { .. } =>
+            compile_stmt_assign(cfg, cur_block, tree, scope, stmt),
         _ => unimplemented!()
     }
 }
 
-fn compile_stmt_expr(cfg: &mut CFG, cur_block: String, stmt: &Statement)
-    -> String {
+/// Lowers `x = y = value` to one `gen_assign_inst` per target, all reading
+/// the same evaluated right-hand side — the same helper the `for` loop's
+/// target binding uses.
+fn compile_stmt_assign(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
+    let (targets, value) = match *stmt {
+        Statement::Assign { ref targets, ref value, .. } => (targets, value),
+        _ => unreachable!()
+    };
+
+    let value_oper = compile_expr(cfg, cur_block.clone(), tree, scope, value);
+    for target in targets.iter() {
+        util::gen_assign_inst(cfg, cur_block.clone(), tree, scope, target,
+            value_oper.clone());
+    }
+
+    cur_block
+}
+
+fn compile_stmt_expr(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
     let expr = match *stmt {
         Statement::Expr { ref value } => value,
         _ => unreachable!()
     };
-    let reg = compile_expr(cfg, cur_block.clone(), expr);
+    compile_expr(cfg, cur_block.clone(), tree, scope, expr);
 
     cur_block
 }
 
-fn compile_expr(cfg: &mut CFG, cur_block: String, expr: &Expression)
-    -> Operand {
+/// Lowers an explicit `return`. Unlike the implicit void return
+/// `gather_main`/`gather_func_def` synthesize on fall-through, this emits
+/// `Return` with the value (if any) attached as `cur_block`'s own
+/// terminator -- it doesn't branch to the shared exit block, since that
+/// block's `Return` is void and would discard the value. Statements after it
+/// continue in a fresh block with no incoming edge.
+fn compile_stmt_return(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
+    let value = match *stmt {
+        Statement::Return { ref value } => value,
+        _ => unreachable!()
+    };
+    let value_oper = value.as_ref()
+        .map(|expr| compile_expr(cfg, cur_block.clone(), tree, scope, expr));
+
+    cfg.add_inst(&cur_block, Instruction::Return(
+        ReturnStruct { return_type: "void".to_string(), value: value_oper }));
+
+    cfg.new_block()
+}
+
+/// Lowers an `if` statement into `then`/`else`/`merge` blocks connected by
+/// conditional branches, mirroring the unconditional branch gather_main
+/// already emits into its exit block.
+fn compile_stmt_if(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
+    let (test, body, orelse) = match *stmt {
+        Statement::If { ref test, ref body, ref orelse } => (test, body, orelse),
+        _ => unreachable!()
+    };
+
+    let test_oper = compile_expr(cfg, cur_block.clone(), tree, scope, test);
+    let then_block = cfg.new_block();
+    let else_block = cfg.new_block();
+    let merge_block = cfg.new_block();
+
+    cfg.connect_blocks(&cur_block, then_block.clone());
+    cfg.connect_blocks(&cur_block, else_block.clone());
+    cfg.add_inst(&cur_block, Instruction::Branch(
+        BranchStruct::new(Some(test_oper), then_block.clone(),
+            Some(else_block.clone()))));
+
+    let then_end = compile_stmts(cfg, then_block, tree, scope, body);
+    cfg.connect_blocks(&then_end, merge_block.clone());
+    cfg.add_inst(&then_end, Instruction::Branch(
+        BranchStruct::new(None, merge_block.clone(), None)));
+
+    let else_end = compile_stmts(cfg, else_block, tree, scope, orelse);
+    cfg.connect_blocks(&else_end, merge_block.clone());
+    cfg.add_inst(&else_end, Instruction::Branch(
+        BranchStruct::new(None, merge_block.clone(), None)));
+
+    merge_block
+}
+
+/// Lowers a `while` statement into `header`/`body`/`exit` blocks. The test is
+/// re-evaluated in `header` on every iteration, `body`'s terminal block
+/// back-edges to `header`, and `orelse` runs off the `exit` path before
+/// control continues.
+fn compile_stmt_while(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
+    let (test, body, orelse) = match *stmt {
+        Statement::While { ref test, ref body, ref orelse } => (test, body, orelse),
+        _ => unreachable!()
+    };
+
+    let header_block = cfg.new_block();
+    let body_block = cfg.new_block();
+    let exit_block = cfg.new_block();
+
+    cfg.connect_blocks(&cur_block, header_block.clone());
+    cfg.add_inst(&cur_block, Instruction::Branch(
+        BranchStruct::new(None, header_block.clone(), None)));
+
+    let test_oper = compile_expr(cfg, header_block.clone(), tree, scope, test);
+    cfg.connect_blocks(&header_block, body_block.clone());
+    cfg.connect_blocks(&header_block, exit_block.clone());
+    cfg.add_inst(&header_block, Instruction::Branch(
+        BranchStruct::new(Some(test_oper), body_block.clone(),
+            Some(exit_block.clone()))));
+
+    let body_end = compile_stmts(cfg, body_block, tree, scope, body);
+    cfg.connect_blocks(&body_end, header_block.clone());
+    cfg.add_inst(&body_end, Instruction::Branch(
+        BranchStruct::new(None, header_block, None)));
+
+    compile_stmts(cfg, exit_block, tree, scope, orelse)
+}
+
+/// Lowers a `for` statement to the iterator protocol (`iter()` on the
+/// iterable, then `next()` on its iterator) wrapped around the same header/body/exit skeleton
+/// `compile_stmt_while` uses.
+fn compile_stmt_for(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, stmt: &Statement) -> String {
+    let (target, iter, body, orelse) = match *stmt {
+        Statement::For { ref target, ref iter, ref body, ref orelse } =>
+            (target, iter, body, orelse),
+        _ => unreachable!()
+    };
+
+    let iter_oper = compile_expr(cfg, cur_block.clone(), tree, scope, iter);
+    let iter_reg = util::gen_call_inst(cfg, cur_block.clone(), "iter",
+        vec![iter_oper]);
+
+    let header_block = cfg.new_block();
+    let body_block = cfg.new_block();
+    let exit_block = cfg.new_block();
+
+    cfg.connect_blocks(&cur_block, header_block.clone());
+    cfg.add_inst(&cur_block, Instruction::Branch(
+        BranchStruct::new(None, header_block.clone(), None)));
+
+    let next_reg = util::gen_call_inst(cfg, header_block.clone(), "next",
+        vec![iter_reg]);
+    let has_next = util::gen_call_inst(cfg, header_block.clone(), "hasnext",
+        vec![next_reg.clone()]);
+    cfg.connect_blocks(&header_block, body_block.clone());
+    cfg.connect_blocks(&header_block, exit_block.clone());
+    cfg.add_inst(&header_block, Instruction::Branch(
+        BranchStruct::new(Some(has_next), body_block.clone(),
+            Some(exit_block.clone()))));
+
+    util::gen_assign_inst(cfg, body_block.clone(), tree, scope, target, next_reg);
+    let body_end = compile_stmts(cfg, body_block, tree, scope, body);
+    cfg.connect_blocks(&body_end, header_block.clone());
+    cfg.add_inst(&body_end, Instruction::Branch(
+        BranchStruct::new(None, header_block, None)));
+
+    compile_stmts(cfg, exit_block, tree, scope, orelse)
+}
+
+fn compile_expr(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, expr: &Expression) -> Operand {
+    tree.set_scope_for(expr_id(expr), scope);
+
     match *expr {
-        Expression::BinOp { .. } => compile_expr_binop(cfg, cur_block, expr),
+        Expression::BinOp { .. } =>
+            compile_expr_binop(cfg, cur_block, tree, scope, expr),
         Expression::Num { ref n } => util::gen_imm_num(cfg, cur_block, n),
+        Expression::Name { ref id, .. } => {
+            let (def_scope, offset) = tree.resolve(scope, id)
+                .unwrap_or_else(|e| panic!("{:?}", e));
+            Operand::Local(def_scope, offset)
+        },
+        Expression::Call { .. } =>
+            compile_expr_call(cfg, cur_block, tree, scope, expr),
         _ => unimplemented!()
     }
 }
 
-fn compile_expr_binop(cfg: &mut CFG, cur_block: String, expr: &Expression)
-    -> Operand {
+fn compile_expr_binop(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, expr: &Expression) -> Operand {
     let (left, op, right) = match *expr {
         Expression::BinOp { ref left, ref op, ref right } => (left, op, right),
         _ => unreachable!()
     };
 
-    let lft_oper = compile_expr(cfg, cur_block.clone(), left);
-    let rht_oper = compile_expr(cfg, cur_block.clone(), right);
+    let lft_oper = compile_expr(cfg, cur_block.clone(), tree, scope, left);
+    let rht_oper = compile_expr(cfg, cur_block.clone(), tree, scope, right);
     util::gen_bin_inst(cfg, cur_block, op, lft_oper, rht_oper)
 }
+
+/// Resolves the callee by name through the scope tree and lowers the call
+/// under that bare name, so it matches a `Function` in `Program.funcs`
+/// stored under the same name; methods, stored as `Class.method`, are not
+/// matched by such a call.
+fn compile_expr_call(cfg: &mut CFG, cur_block: String, tree: &mut ScopeTree,
+    scope: ScopeId, expr: &Expression) -> Operand {
+    let (func, args) = match *expr {
+        Expression::Call { ref func, ref args, .. } => (func, args),
+        _ => unreachable!()
+    };
+    let name = match **func {
+        Expression::Name { ref id, .. } => id.clone(),
+        _ => unimplemented!()
+    };
+    // Resolving confirms the callee is a name actually bound in scope
+    // (a gathered `FunctionDef`) before emitting the call by name.
+    tree.resolve(scope, &name).unwrap_or_else(|e| panic!("{:?}", e));
+
+    let arg_opers = args.iter()
+        .map(|arg| compile_expr(cfg, cur_block.clone(), tree, scope, arg))
+        .collect();
+    util::gen_call_inst(cfg, cur_block, &name, arg_opers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: f64) -> Expression {
+        Expression::Num { n }
+    }
+
+    fn name(id: &str) -> Expression {
+        Expression::Name { id: id.to_string() }
+    }
+
+    #[test]
+    fn compile_stmt_if_wires_then_else_into_a_shared_merge_block() {
+        let mut cfg = CFG::new();
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let entry = cfg.entry_block.clone();
+
+        let stmt = Statement::If {
+            test: num(1.0),
+            body: vec![Statement::Expr { value: num(2.0) }],
+            orelse: vec![Statement::Expr { value: num(3.0) }]
+        };
+
+        let merge = compile_stmt(&mut cfg, entry.clone(), &mut tree, root, &stmt);
+
+        // entry, then, else, merge.
+        assert_eq!(cfg.block_names().len(), 4);
+        assert_ne!(merge, entry);
+        assert_eq!(cfg.predecessors(&merge).len(), 2);
+    }
+
+    #[test]
+    fn compile_stmt_while_wires_header_body_and_exit() {
+        let mut cfg = CFG::new();
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let entry = cfg.entry_block.clone();
+
+        let stmt = Statement::While {
+            test: num(1.0),
+            body: vec![Statement::Expr { value: num(2.0) }],
+            orelse: vec![]
+        };
+
+        compile_stmt(&mut cfg, entry, &mut tree, root, &stmt);
+
+        // entry, header, body, exit.
+        assert_eq!(cfg.block_names().len(), 4);
+    }
+
+    #[test]
+    fn compile_stmt_for_wires_header_body_and_exit() {
+        let mut cfg = CFG::new();
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        // `gather_scope` would have bound the loop target ahead of time;
+        // do that by hand since this test drives `compile_stmt` directly.
+        tree.insert(root, "i".to_string(), 0);
+        let entry = cfg.entry_block.clone();
+
+        let stmt = Statement::For {
+            target: name("i"),
+            iter: num(1.0),
+            body: vec![],
+            orelse: vec![]
+        };
+
+        compile_stmt(&mut cfg, entry.clone(), &mut tree, root, &stmt);
+
+        // entry, header, body, exit.
+        assert_eq!(cfg.block_names().len(), 4);
+        // `iter()` is emitted into the entry block before the loop starts.
+        assert!(cfg.instructions(&entry).len() >= 1);
+    }
+
+    #[test]
+    fn compile_stmt_for_resolves_its_target_through_the_shadowing_scope() {
+        let mut cfg = CFG::new();
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "i".to_string(), 0);
+
+        // A child scope shadowing "i" at a different offset, as a nested
+        // block's own local would.
+        let child = tree.push_scope(root);
+        tree.insert(child, "i".to_string(), 1);
+
+        let entry = cfg.entry_block.clone();
+        let stmt = Statement::For {
+            target: name("i"),
+            iter: num(1.0),
+            body: vec![],
+            orelse: vec![]
+        };
+
+        compile_stmt(&mut cfg, entry, &mut tree, child, &stmt);
+
+        let resolved_into_child = cfg.block_names().into_iter()
+            .any(|b| cfg.instructions(&b).into_iter().any(|inst| match inst {
+                Instruction::Assign(assign) =>
+                    assign.target == Operand::Local(child, 1),
+                _ => false
+            }));
+        assert!(resolved_into_child, "expected the for-loop target to \
+            assign into the shadowing (child) scope, not the outer one");
+    }
+
+    #[test]
+    fn compile_stmt_assign_resolves_its_target_through_the_shadowing_scope() {
+        let mut cfg = CFG::new();
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "x".to_string(), 0);
+
+        let child = tree.push_scope(root);
+        tree.insert(child, "x".to_string(), 1);
+
+        let entry = cfg.entry_block.clone();
+        let stmt = Statement::Assign {
+            targets: vec![name("x")],
+            value: num(2.0)
+        };
+
+        compile_stmt(&mut cfg, entry.clone(), &mut tree, child, &stmt);
+
+        let insts = cfg.instructions(&entry);
+        assert!(insts.into_iter().any(|inst| match inst {
+            Instruction::Assign(assign) =>
+                assign.target == Operand::Local(child, 1),
+            _ => false
+        }), "expected the assignment to resolve \"x\" into the shadowing \
+            (child) scope, not the outer (root) one");
+    }
+
+    fn empty_args() -> Arguments {
+        Arguments::Arguments { args: vec![], vararg: None, kwonlyargs: vec![],
+            kw_defaults: vec![], kwarg: None, defaults: vec![] }
+    }
+
+    fn self_args() -> Arguments {
+        Arguments::Arguments {
+            args: vec![Arg::Arg { arg: "self".to_string(), annotation: None }],
+            vararg: None, kwonlyargs: vec![], kw_defaults: vec![],
+            kwarg: None, defaults: vec![] }
+    }
+
+    #[test]
+    fn gather_func_def_synthesizes_implicit_void_return_on_fallthrough() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+
+        let stmt = Statement::FunctionDef { name: "f".to_string(),
+            args: empty_args(), body: vec![Statement::Expr { value: num(1.0) }] };
+        let mut modules = ModuleCache::new();
+
+        let (func, _func_scope) = gather_func_def(&stmt, &mut tree, root, 0,
+            "./", &mut modules, None);
+
+        assert_eq!(func.name, "f");
+        let exit = func.graph.exit_block.clone();
+        assert!(func.graph.instructions(&exit).into_iter().any(|inst| match inst {
+            Instruction::Return(_) => true,
+            _ => false
+        }));
+    }
+
+    #[test]
+    fn gather_func_def_binds_a_plain_local_assigned_in_the_body() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        // `def f(): y = 1` -- a plain local, never bound by
+        // `gather_func_params` (which only handles parameters).
+        let stmt = Statement::FunctionDef { name: "f".to_string(),
+            args: empty_args(), body: vec![Statement::Assign {
+                targets: vec![name("y")], value: num(1.0) }] };
+
+        let (func, _func_scope) = gather_func_def(&stmt, &mut tree, root, 0,
+            "./", &mut modules, None);
+
+        assert!(func.graph.block_names().into_iter()
+            .any(|b| func.graph.instructions(&b).into_iter()
+                .any(|inst| match inst {
+                    Instruction::Assign(_) => true,
+                    _ => false
+                })), "expected \"y\" to resolve into the function's own \
+                scope instead of panicking or aliasing an outer binding");
+    }
+
+    #[test]
+    fn gather_func_def_returns_its_explicit_value_instead_of_discarding_it() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        // `return 1 + 1`, folded down to a single Imm by level 1.
+        let stmt = Statement::FunctionDef { name: "f".to_string(),
+            args: empty_args(), body: vec![Statement::Return {
+                value: Some(Expression::BinOp { left: Box::new(num(1.0)),
+                    op: Operator::Add, right: Box::new(num(1.0)) }) }] };
+
+        let (func, _func_scope) = gather_func_def(&stmt, &mut tree, root, 1,
+            "./", &mut modules, None);
+
+        let returned: Vec<Operand> = func.graph.block_names().into_iter()
+            .flat_map(|b| func.graph.instructions(&b))
+            .filter_map(|inst| match inst {
+                Instruction::Return(ret) => ret.value,
+                _ => None
+            })
+            .collect();
+
+        assert_eq!(returned, vec![Operand::Imm(2.0)], "expected the \
+            explicit return's value to survive into the CFG instead of \
+            being discarded in favor of the synthesized void return");
+    }
+
+    #[test]
+    fn gather_funcs_recurses_into_nested_function_defs() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        let inner = Statement::FunctionDef { name: "inner".to_string(),
+            args: empty_args(), body: vec![] };
+        let outer = Statement::FunctionDef { name: "outer".to_string(),
+            args: empty_args(), body: vec![inner] };
+
+        let funcs = gather_funcs(&vec![outer], &mut tree, root, 0, "./",
+            &mut modules, None);
+
+        let names: Vec<String> = funcs.iter().map(|f| f.name.clone()).collect();
+        assert!(names.contains(&"outer".to_string()));
+        assert!(names.contains(&"inner".to_string()));
+    }
+
+    #[test]
+    fn gather_funcs_compiles_classdef_methods_including_init() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        let init = Statement::FunctionDef { name: "__init__".to_string(),
+            args: empty_args(), body: vec![] };
+        let class = Statement::ClassDef { name: "Foo".to_string(),
+            body: vec![init] };
+
+        let funcs = gather_funcs(&vec![class], &mut tree, root, 0, "./",
+            &mut modules, None);
+
+        let names: Vec<String> = funcs.iter().map(|f| f.name.clone()).collect();
+        assert_eq!(names, vec!["Foo.__init__".to_string()]);
+    }
+
+    #[test]
+    fn gather_funcs_qualifies_same_named_methods_by_their_class() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        let init_a = Statement::FunctionDef { name: "__init__".to_string(),
+            args: empty_args(), body: vec![] };
+        let init_b = Statement::FunctionDef { name: "__init__".to_string(),
+            args: empty_args(), body: vec![] };
+        let class_a = Statement::ClassDef { name: "A".to_string(),
+            body: vec![init_a] };
+        let class_b = Statement::ClassDef { name: "B".to_string(),
+            body: vec![init_b] };
+
+        // Two classes both defining `__init__` must not collide under the
+        // same flat name in `Program.funcs`.
+        let funcs = gather_funcs(&vec![class_a, class_b], &mut tree, root, 0,
+            "./", &mut modules, None);
+
+        let names: Vec<String> = funcs.iter().map(|f| f.name.clone()).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"A.__init__".to_string()));
+        assert!(names.contains(&"B.__init__".to_string()));
+    }
+
+    #[test]
+    fn gather_funcs_compiles_an_init_that_assigns_a_self_attribute() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        // `self.x = 1`, the shape `gather_class_init` exists to detect.
+        let assign = Statement::Assign {
+            targets: vec![Expression::Attribute {
+                value: Box::new(name("self")), attr: "x".to_string() }],
+            value: num(1.0) };
+        let init = Statement::FunctionDef { name: "__init__".to_string(),
+            args: self_args(), body: vec![assign] };
+        let class = Statement::ClassDef { name: "Foo".to_string(),
+            body: vec![init] };
+
+        let funcs = gather_funcs(&vec![class], &mut tree, root, 0, "./",
+            &mut modules, None);
+
+        let init_func = funcs.iter().find(|f| f.name == "Foo.__init__")
+            .expect("expected Foo.__init__ in the compiled functions");
+        assert!(init_func.graph.block_names().into_iter()
+            .any(|b| init_func.graph.instructions(&b).into_iter()
+                .any(|inst| match inst {
+                    Instruction::Assign(_) => true,
+                    _ => false
+                })), "expected the self.x assignment to compile into an \
+                Assign instruction instead of panicking");
+    }
+
+    #[test]
+    fn gather_scope_rejects_a_relative_import_instead_of_guessing_its_path() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let mut modules = ModuleCache::new();
+
+        let stmt = Statement::ImportFrom { module: "pkg".to_string(),
+            names: vec![Alias::Alias { name: "x".to_string(), asname: None }],
+            level: 1 };
+
+        match util::gather_scope(&mut tree, root, &vec![stmt], 0, false,
+            "./", &mut modules) {
+            Err(CompilerError::IOError(_)) => (),
+            other => panic!("expected a relative import to surface as an \
+                IOError instead of resolving against the wrong path, \
+                got {:?}", other)
+        }
+    }
+}
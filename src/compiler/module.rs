@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+
+use ::parser;
+use ::parser::ast::{Ast, Statement, Expression};
+use super::errors::CompilerError;
+use super::scope::{ScopeTree, ScopeId};
+use super::util;
+
+/// The names a single module exports: its gathered top-level `ScopeTree`
+/// plus, when the module defines one, the `__all__` list that narrows what
+/// `from m import *` actually pulls in.
+pub struct ScopeInfo {
+    tree: ScopeTree,
+    root: ScopeId,
+    all: Option<Vec<String>>,
+}
+
+impl ScopeInfo {
+    /// Whether `name` is bound at the module's top level.
+    fn has(&self, name: &str) -> bool {
+        self.tree.resolve(self.root, name).is_ok()
+    }
+
+    /// The names a wildcard import should bind: `__all__` if the module
+    /// declared one, otherwise everything gathered at module scope.
+    fn exported_names(&self) -> Vec<String> {
+        match self.all {
+            Some(ref names) => names.clone(),
+            None => self.tree.names_in(self.root),
+        }
+    }
+}
+
+/// Resolves and caches the `ScopeInfo` for imported modules, keyed by the
+/// resolved file path, so `from m import ...` appearing more than once only
+/// parses `m` once. A cycle between modules importing each other is
+/// reported as an `IOError` instead of being recursed into.
+pub struct ModuleCache {
+    cache: HashMap<String, ScopeInfo>,
+    in_progress: HashSet<String>,
+}
+
+impl ModuleCache {
+    pub fn new() -> ModuleCache {
+        ModuleCache { cache: HashMap::new(), in_progress: HashSet::new() }
+    }
+
+    /// Resolves `module` (as named in a `from module import ...`) relative
+    /// to `src_root`, parsing and gathering its scope on first use.
+    pub fn resolve(&mut self, src_root: &str, module: &str)
+        -> Result<&ScopeInfo, CompilerError> {
+        let path = module_path(src_root, module);
+
+        if !self.cache.contains_key(&path) {
+            if !self.in_progress.insert(path.clone()) {
+                return Err(CompilerError::IOError(format!(
+                    "circular import involving module '{}'", module)));
+            }
+
+            let info = gather_module_scope(&path, self)?;
+            self.in_progress.remove(&path);
+            self.cache.insert(path.clone(), info);
+        }
+
+        Ok(&self.cache[&path])
+    }
+
+    /// Checks that `module` binds `name` at its top level, returning a
+    /// `NameError` if it doesn't.
+    pub fn resolve_name(&mut self, src_root: &str, module: &str, name: &str)
+        -> Result<(), CompilerError> {
+        if !self.resolve(src_root, module)?.has(name) {
+            return Err(CompilerError::NameError(name.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// All names `from module import *` should bind.
+    pub fn wildcard_names(&mut self, src_root: &str, module: &str)
+        -> Result<Vec<String>, CompilerError> {
+        Ok(self.resolve(src_root, module)?.exported_names())
+    }
+}
+
+/// Turns a dotted module name into the `.py` file it names, rooted at the
+/// importing file's `src_root` (the same root `get_file_prefix` returns).
+fn module_path(src_root: &str, module: &str) -> String {
+    format!("{}{}.py", src_root, module.replace('.', "/"))
+}
+
+fn gather_module_scope(path: &str, modules: &mut ModuleCache)
+    -> Result<ScopeInfo, CompilerError> {
+    let mut src = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut src))
+        .map_err(|_| CompilerError::IOError(format!(
+            "module '{}' not found", path)))?;
+
+    let ast = parser::parse(&src)
+        .map_err(|_| CompilerError::IOError(format!(
+            "could not parse module '{}'", path)))?;
+    let body = match ast {
+        Ast::Module { body } => body
+    };
+
+    let (root_dir, _) = util::get_file_prefix(path)?;
+
+    let mut tree = ScopeTree::new();
+    let root = tree.root();
+    util::gather_scope(&mut tree, root, &body, 0, false, &root_dir, modules)?;
+
+    let all = gather_dunder_all(&body);
+
+    Ok(ScopeInfo { tree, root, all })
+}
+
+/// Pulls the string elements out of a top-level `__all__ = [...]` (or
+/// `(...)`) assignment, if the module declares one.
+fn gather_dunder_all(body: &Vec<Statement>) -> Option<Vec<String>> {
+    for stmt in body.iter() {
+        if let Statement::Assign { ref targets, ref value } = *stmt {
+            let is_dunder_all = targets.iter().any(|target| match *target {
+                Expression::Name { ref id, .. } => id == "__all__",
+                _ => false
+            });
+
+            if !is_dunder_all {
+                continue
+            }
+
+            let elts = match *value {
+                Expression::List { ref elts, .. } => elts,
+                Expression::Tuple { ref elts, .. } => elts,
+                _ => continue
+            };
+
+            return Some(elts.iter().filter_map(|elt| match *elt {
+                Expression::Str { ref s } => Some(s.clone()),
+                _ => None
+            }).collect());
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn module_path_turns_a_dotted_name_into_a_rooted_py_file() {
+        assert_eq!(module_path("./src/", "pkg.mod"), "./src/pkg/mod.py");
+    }
+
+    #[test]
+    fn has_checks_only_the_modules_own_top_level_scope() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "a".to_string(), 0);
+        let child = tree.push_scope(root);
+        tree.insert(child, "nested".to_string(), 0);
+
+        let info = ScopeInfo { tree, root, all: None };
+
+        assert!(info.has("a"));
+        assert!(!info.has("missing"));
+        assert!(!info.has("nested"));
+    }
+
+    #[test]
+    fn exported_names_falls_back_to_everything_gathered_without_dunder_all() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "a".to_string(), 0);
+        tree.insert(root, "b".to_string(), 1);
+
+        let info = ScopeInfo { tree, root, all: None };
+
+        let mut names = info.exported_names();
+        names.sort();
+        assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn exported_names_narrows_to_dunder_all_when_present() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "a".to_string(), 0);
+        tree.insert(root, "b".to_string(), 1);
+
+        let info = ScopeInfo { tree, root, all: Some(vec!["a".to_string()]) };
+
+        assert_eq!(info.exported_names(), vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn resolve_surfaces_io_error_for_a_missing_module_file() {
+        let mut modules = ModuleCache::new();
+
+        match modules.resolve("./no-such-cannoli-test-src-root/", "missing") {
+            Err(CompilerError::IOError(_)) => (),
+            other => panic!("expected an IOError for a missing module file, \
+                got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn resolve_rejects_a_circular_import_instead_of_recursing_forever() {
+        let root = std::env::temp_dir()
+            .join("cannoli_module_cache_circular_import_test");
+        fs::create_dir_all(&root).expect("failed to create test src root");
+        fs::write(root.join("module_a.py"), "from module_b import *\n")
+            .expect("failed to write module_a.py");
+        fs::write(root.join("module_b.py"), "from module_a import *\n")
+            .expect("failed to write module_b.py");
+
+        let src_root = format!("{}/", root.to_str().unwrap());
+        let mut modules = ModuleCache::new();
+
+        match modules.resolve(&src_root, "module_a") {
+            Err(CompilerError::IOError(_)) => (),
+            other => panic!("expected a circular import to surface as an \
+                IOError instead of recursing forever, got {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashSet, VecDeque};
+
+use super::cfg::CFG;
+use super::program::Program;
+
+impl Program {
+    /// Removes `Function`s never transitively called from `main`, iterating
+    /// to a fixpoint since pruning one function's calls can strand another.
+    pub fn eliminate_dead_code(&mut self) {
+        loop {
+            for func in self.funcs.iter_mut() {
+                func.graph.eliminate_dead_blocks();
+            }
+
+            let reachable = self.reachable_funcs();
+            let before = self.funcs.len();
+            self.funcs.retain(|f| f.name == "main" || reachable.contains(&f.name));
+
+            if self.funcs.len() == before {
+                break
+            }
+        }
+    }
+
+    /// Functions transitively reachable from `main` via call instructions.
+    fn reachable_funcs(&self) -> HashSet<String> {
+        let mut reachable = HashSet::new();
+        let mut worklist = VecDeque::new();
+        worklist.push_back("main".to_string());
+
+        while let Some(name) = worklist.pop_front() {
+            if !reachable.insert(name.clone()) {
+                continue
+            }
+
+            if let Some(func) = self.funcs.iter().find(|f| f.name == name) {
+                for callee in func.graph.called_funcs() {
+                    if !reachable.contains(&callee) {
+                        worklist.push_back(callee);
+                    }
+                }
+            }
+        }
+
+        reachable
+    }
+}
+
+impl CFG {
+    /// Prunes basic blocks unreachable from `entry_block`, following every
+    /// `BranchStruct` successor edge to mark what's live.
+    pub fn eliminate_dead_blocks(&mut self) {
+        let reachable = self.reachable_blocks();
+        self.retain_blocks(&reachable);
+    }
+
+    /// Blocks reachable from `entry_block` by following branch targets.
+    fn reachable_blocks(&self) -> HashSet<String> {
+        let mut reachable = HashSet::new();
+        let mut worklist = VecDeque::new();
+        worklist.push_back(self.entry_block.clone());
+
+        while let Some(block) = worklist.pop_front() {
+            if !reachable.insert(block.clone()) {
+                continue
+            }
+
+            for succ in self.successors(&block) {
+                if !reachable.contains(&succ) {
+                    worklist.push_back(succ);
+                }
+            }
+        }
+
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::function::Function;
+    use super::super::cfg::inst::{Instruction, BranchStruct, CallStruct};
+
+    #[test]
+    fn eliminate_dead_blocks_prunes_a_block_with_no_incoming_edge() {
+        let mut cfg = CFG::new();
+        let entry = cfg.entry_block.clone();
+        let exit = cfg.exit_block.clone();
+        cfg.connect_blocks(&entry, exit.clone());
+        cfg.add_inst(&entry, Instruction::Branch(
+            BranchStruct::new(None, exit.clone(), None)));
+
+        let dangling = cfg.new_block();
+        assert!(cfg.block_names().contains(&dangling));
+
+        cfg.eliminate_dead_blocks();
+
+        assert!(!cfg.block_names().contains(&dangling));
+        assert!(cfg.block_names().contains(&entry));
+    }
+
+    #[test]
+    fn eliminate_dead_code_drops_functions_unreachable_from_main() {
+        let mut main_cfg = CFG::new();
+        let entry = main_cfg.entry_block.clone();
+        let exit = main_cfg.exit_block.clone();
+        let dest = main_cfg.new_temp();
+        main_cfg.add_inst(&entry, Instruction::Call(
+            CallStruct::new(Some(dest), "used".to_string(), vec![])));
+        main_cfg.connect_blocks(&entry, exit.clone());
+        main_cfg.add_inst(&entry, Instruction::Branch(
+            BranchStruct::new(None, exit, None)));
+
+        let mut program = Program { funcs: vec![
+            Function { name: "main".to_string(), return_type: "void".to_string(),
+                graph: main_cfg },
+            Function { name: "used".to_string(), return_type: "void".to_string(),
+                graph: CFG::new() },
+            Function { name: "unused".to_string(), return_type: "void".to_string(),
+                graph: CFG::new() }
+        ] };
+
+        program.eliminate_dead_code();
+
+        let names: Vec<String> = program.funcs.iter()
+            .map(|f| f.name.clone()).collect();
+        assert!(names.contains(&"main".to_string()));
+        assert!(names.contains(&"used".to_string()));
+        assert!(!names.contains(&"unused".to_string()));
+    }
+
+    #[test]
+    fn eliminate_dead_code_prunes_a_transitive_call_chain_behind_a_dead_block() {
+        let mut main_cfg = CFG::new();
+        let entry = main_cfg.entry_block.clone();
+        let exit = main_cfg.exit_block.clone();
+        main_cfg.connect_blocks(&entry, exit.clone());
+        main_cfg.add_inst(&entry, Instruction::Branch(
+            BranchStruct::new(None, exit, None)));
+
+        // "a" is only called from a block with no incoming edge, so a naive
+        // scan of every Call instruction in `main` would see it as called --
+        // but dead-block elimination drops the block (and the call with it)
+        // before reachability is computed from what's left.
+        let dead_block = main_cfg.new_block();
+        main_cfg.add_inst(&dead_block, Instruction::Call(
+            CallStruct::new(None, "a".to_string(), vec![])));
+
+        let mut a_cfg = CFG::new();
+        let a_entry = a_cfg.entry_block.clone();
+        let a_exit = a_cfg.exit_block.clone();
+        a_cfg.add_inst(&a_entry, Instruction::Call(
+            CallStruct::new(None, "b".to_string(), vec![])));
+        a_cfg.connect_blocks(&a_entry, a_exit.clone());
+        a_cfg.add_inst(&a_entry, Instruction::Branch(
+            BranchStruct::new(None, a_exit, None)));
+
+        let mut program = Program { funcs: vec![
+            Function { name: "main".to_string(), return_type: "void".to_string(),
+                graph: main_cfg },
+            Function { name: "a".to_string(), return_type: "void".to_string(),
+                graph: a_cfg },
+            Function { name: "b".to_string(), return_type: "void".to_string(),
+                graph: CFG::new() }
+        ] };
+
+        program.eliminate_dead_code();
+
+        // With the dead block gone, main calls nothing, and the transitive
+        // reachability walk drops "a" and "b" together in the same pass.
+        let names: Vec<String> = program.funcs.iter()
+            .map(|f| f.name.clone()).collect();
+        assert!(names.contains(&"main".to_string()));
+        assert!(!names.contains(&"a".to_string()));
+        assert!(!names.contains(&"b".to_string()));
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use ::parser::ast::{Statement, Expression};
+use super::errors::CompilerError;
+
+/// Identifies a single lexical scope within a `ScopeTree`.
+pub type ScopeId = usize;
+
+/// Identifies an AST node (statement or expression) whose active scope is
+/// tracked by the tree.
+pub type NodeId = usize;
+
+/// A single scope's bindings plus a link to its enclosing scope.
+struct ScopeData {
+    parent: Option<ScopeId>,
+    entries: HashMap<String, usize>,
+}
+
+impl ScopeData {
+    fn new(parent: Option<ScopeId>) -> ScopeData {
+        ScopeData { parent, entries: HashMap::new() }
+    }
+}
+
+/// A lexical scope tree: scopes are addressed by `ScopeId` and link to their
+/// parent, so nested shadowing is representable, and `scope_for` records
+/// which scope was active at a given AST node for later passes to query.
+pub struct ScopeTree {
+    scopes: Vec<ScopeData>,
+    scope_for: HashMap<NodeId, ScopeId>,
+}
+
+impl ScopeTree {
+    /// Creates a tree with a single root scope (e.g. module scope).
+    pub fn new() -> ScopeTree {
+        ScopeTree {
+            scopes: vec![ScopeData::new(None)],
+            scope_for: HashMap::new(),
+        }
+    }
+
+    /// The id of the tree's root scope.
+    pub fn root(&self) -> ScopeId {
+        0
+    }
+
+    /// Creates a new child scope of `parent` and returns its id. Used for
+    /// function parameter scopes and comprehension target scopes, which
+    /// previously lived in their own isolated `HashMap`s.
+    pub fn push_scope(&mut self, parent: ScopeId) -> ScopeId {
+        self.scopes.push(ScopeData::new(Some(parent)));
+        self.scopes.len() - 1
+    }
+
+    /// Binds `name` to `offset` within `scope`.
+    pub fn insert(&mut self, scope: ScopeId, name: String, offset: usize) {
+        self.scopes[scope].entries.insert(name, offset);
+    }
+
+    /// Records that `node` is evaluated with `scope` active, so a later pass
+    /// can recover the right scope from just the node.
+    pub fn set_scope_for(&mut self, node: NodeId, scope: ScopeId) {
+        self.scope_for.insert(node, scope);
+    }
+
+    /// Looks up the scope that was active at `node`, if recorded.
+    pub fn scope_of(&self, node: NodeId) -> Option<ScopeId> {
+        self.scope_for.get(&node).cloned()
+    }
+
+    /// Resolves `id` starting at `scope` and walking parent links until a
+    /// binding is found, returning the defining scope and its offset within
+    /// that scope.
+    pub fn resolve(&self, scope: ScopeId, id: &str)
+        -> Result<(ScopeId, usize), CompilerError> {
+        let mut cur = Some(scope);
+        while let Some(ndx) = cur {
+            if let Some(offset) = self.scopes[ndx].entries.get(id) {
+                return Ok((ndx, *offset))
+            }
+            cur = self.scopes[ndx].parent;
+        }
+
+        Err(CompilerError::NameError(id.to_string()))
+    }
+
+    /// All names bound directly in `scope` (not its ancestors). Used by
+    /// module resolution to answer "what does this module export" for
+    /// wildcard imports.
+    pub fn names_in(&self, scope: ScopeId) -> Vec<String> {
+        self.scopes[scope].entries.keys().cloned().collect()
+    }
+}
+
+/// The AST carries no id of its own, so a node's address is the `NodeId`
+/// `compile_stmt` records it under via `set_scope_for`.
+pub fn stmt_id(stmt: &Statement) -> NodeId {
+    stmt as *const Statement as NodeId
+}
+
+/// Same trick as `stmt_id`, for the expression side `compile_expr` records.
+pub fn expr_id(expr: &Expression) -> NodeId {
+    expr as *const Expression as NodeId
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_walks_up_to_a_parent_scope() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "x".to_string(), 0);
+
+        let child = tree.push_scope(root);
+        tree.insert(child, "y".to_string(), 0);
+
+        assert_eq!(tree.resolve(child, "x").unwrap(), (root, 0));
+        assert!(tree.resolve(root, "y").is_err());
+    }
+
+    #[test]
+    fn child_scope_shadows_parent_binding() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "x".to_string(), 0);
+
+        let child = tree.push_scope(root);
+        tree.insert(child, "x".to_string(), 1);
+
+        assert_eq!(tree.resolve(child, "x").unwrap(), (child, 1));
+        assert_eq!(tree.resolve(root, "x").unwrap(), (root, 0));
+    }
+
+    #[test]
+    fn names_in_excludes_ancestor_bindings() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        tree.insert(root, "x".to_string(), 0);
+
+        let child = tree.push_scope(root);
+        tree.insert(child, "y".to_string(), 0);
+
+        assert_eq!(tree.names_in(child), vec!["y".to_string()]);
+    }
+
+    #[test]
+    fn scope_for_roundtrips_through_a_node_address() {
+        let mut tree = ScopeTree::new();
+        let root = tree.root();
+        let child = tree.push_scope(root);
+
+        let stmt = Statement::Expr { value: Expression::Num { n: 1.0 } };
+        tree.set_scope_for(stmt_id(&stmt), child);
+
+        assert_eq!(tree.scope_of(stmt_id(&stmt)), Some(child));
+    }
+}
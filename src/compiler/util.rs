@@ -1,8 +1,13 @@
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 
 use ::parser::ast::*;
 use super::errors::CompilerError;
+use super::cfg::CFG;
+use super::cfg::inst::{Instruction, CallStruct, AssignStruct};
+use super::cfg::operand::Operand;
+use super::module::ModuleCache;
+use super::scope::{ScopeTree, ScopeId};
 
 /// Returns the root directory of the given file and the file name sans ext
 pub fn get_file_prefix(file: &str) -> Result<(String, String), CompilerError> {
@@ -26,27 +31,30 @@ pub fn get_file_prefix(file: &str) -> Result<(String, String), CompilerError> {
 }
 
 // Scope gathering helper functions
-/// This function gathers id's that will be instantiated in the current scope
-/// and orders them for the compiler to use when looking up or assigning values
-pub fn gather_scope(stmts: &Vec<Statement>, start_ndx: usize, is_class: bool)
-    -> Result<HashMap<String, usize>, CompilerError> {
+/// This function gathers id's that will be instantiated in `scope` and binds
+/// them into the given `ScopeTree`, ordering them for the compiler to use
+/// when looking up or assigning values. `src_root`/`modules` let
+/// `ImportFrom` statements resolve and cache the modules they reference.
+pub fn gather_scope(tree: &mut ScopeTree, scope: ScopeId,
+    stmts: &Vec<Statement>, start_ndx: usize, is_class: bool, src_root: &str,
+    modules: &mut ModuleCache) -> Result<(), CompilerError> {
     let mut scope_set = HashSet::new();
-    let mut scope_map = HashMap::new();
 
-    rec_gather_scope(&mut scope_set, stmts, is_class)?;
+    rec_gather_scope(&mut scope_set, stmts, is_class, src_root, modules)?;
 
     let end_ndx = start_ndx + scope_set.len();
     (start_ndx..end_ndx).into_iter().zip(scope_set.into_iter())
         .for_each(|(ndx, key)| {
-            scope_map.insert(key, ndx);
+            tree.insert(scope, key, ndx);
         });
 
-    Ok(scope_map)
+    Ok(())
 }
 
 /// Recursively identifies statements that will modify a single level of scope
 fn rec_gather_scope(scope: &mut HashSet<String>, stmts: &Vec<Statement>,
-    is_class: bool) -> Result<(), CompilerError> {
+    is_class: bool, src_root: &str, modules: &mut ModuleCache)
+    -> Result<(), CompilerError> {
     for stmt in stmts.iter() {
         match *stmt {
             Statement::FunctionDef { ref name, .. } => {
@@ -66,16 +74,16 @@ fn rec_gather_scope(scope: &mut HashSet<String>, stmts: &Vec<Statement>,
             },
             Statement::For { ref target, iter: _, ref body, ref orelse } => {
                 unpack_assign_targets(scope, target)?;
-                rec_gather_scope(scope, body, is_class)?;
-                rec_gather_scope(scope, orelse, is_class)?;
+                rec_gather_scope(scope, body, is_class, src_root, modules)?;
+                rec_gather_scope(scope, orelse, is_class, src_root, modules)?;
             },
             Statement::While { test: _, ref body, ref orelse } => {
-                rec_gather_scope(scope, body, is_class)?;
-                rec_gather_scope(scope, orelse, is_class)?;
+                rec_gather_scope(scope, body, is_class, src_root, modules)?;
+                rec_gather_scope(scope, orelse, is_class, src_root, modules)?;
             },
             Statement::If { test: _, ref body, ref orelse } => {
-                rec_gather_scope(scope, body, is_class)?;
-                rec_gather_scope(scope, orelse, is_class)?;
+                rec_gather_scope(scope, body, is_class, src_root, modules)?;
+                rec_gather_scope(scope, orelse, is_class, src_root, modules)?;
             },
             Statement::Import { ref names } => {
                 for name in names.iter() {
@@ -90,11 +98,36 @@ fn rec_gather_scope(scope: &mut HashSet<String>, stmts: &Vec<Statement>,
                     scope.insert(alias.clone());
                 }
             },
-            Statement::ImportFrom { .. } => {
-                // TODO, if we wanted to support import from we are going to
-                // run into issues with wildcards. We would need to gather
-                // the scope of the entire module.
-                unimplemented!();
+            // `level` is the number of leading dots on a relative
+            // `from . import x` / `from ..pkg import x`. Relative imports
+            // aren't resolved against the importing module's own path, so
+            // rather than silently resolving `module` against the wrong
+            // directory, reject them outright.
+            Statement::ImportFrom { ref module, ref names, level } => {
+                if level > 0 {
+                    return Err(CompilerError::IOError(format!(
+                        "relative import of '{}' is not supported", module)));
+                }
+
+                for name in names.iter() {
+                    let (name, asname) = match *name {
+                        Alias::Alias { ref name, ref asname } => (name, asname)
+                    };
+
+                    if name == "*" {
+                        for exported in modules.wildcard_names(src_root, module)? {
+                            scope.insert(exported);
+                        }
+                    } else {
+                        modules.resolve_name(src_root, module, name)?;
+                        let alias = match *asname {
+                            Some(ref alias) => alias,
+                            None => name
+                        };
+
+                        scope.insert(alias.clone());
+                    }
+                }
             },
             _ => ()
         }
@@ -103,10 +136,12 @@ fn rec_gather_scope(scope: &mut HashSet<String>, stmts: &Vec<Statement>,
     Ok(())
 }
 
-pub fn gather_func_params(params: &Arguments, start_ndx: usize)
-    -> Result<HashMap<String, usize>, CompilerError> {
+/// Pushes a child scope of `parent` holding the function's parameters and
+/// returns its id, so call sites thread it through as the scope active for
+/// the function body instead of carrying around a standalone map.
+pub fn gather_func_params(tree: &mut ScopeTree, parent: ScopeId,
+    params: &Arguments, start_ndx: usize) -> Result<ScopeId, CompilerError> {
     let mut scope_set = HashSet::new();
-    let mut scope_map = HashMap::new();
     let (args, _vararg, _kwonlyargs, _kw_defaults, _kwarg, _defaults) =
     match *params {
         Arguments::Arguments { ref args, ref vararg, ref kwonlyargs,
@@ -122,19 +157,22 @@ pub fn gather_func_params(params: &Arguments, start_ndx: usize)
         scope_set.insert(arg_name.to_string());
     }
 
+    let scope = tree.push_scope(parent);
     let end_ndx = start_ndx + scope_set.len();
     (start_ndx..end_ndx).into_iter().zip(scope_set.into_iter())
         .for_each(|(ndx, key)| {
-            scope_map.insert(key, ndx);
+            tree.insert(scope, key, ndx);
         });
 
-    Ok(scope_map)
+    Ok(scope)
 }
 
-pub fn gather_comp_targets(generators: &Vec<Comprehension>, start_ndx: usize)
-    -> Result<HashMap<String, usize>, CompilerError> {
+/// Pushes a child scope of `parent` holding a comprehension's targets and
+/// returns its id, the same treatment `gather_func_params` gives arguments.
+pub fn gather_comp_targets(tree: &mut ScopeTree, parent: ScopeId,
+    generators: &Vec<Comprehension>, start_ndx: usize)
+    -> Result<ScopeId, CompilerError> {
     let mut scope_set = HashSet::new();
-    let mut scope_map = HashMap::new();
 
     let mut gen_iter = generators.iter();
     while let Some(&Comprehension::Comprehension { ref target, .. })
@@ -142,13 +180,14 @@ pub fn gather_comp_targets(generators: &Vec<Comprehension>, start_ndx: usize)
         unpack_assign_targets(&mut scope_set, target)?;
     }
 
+    let scope = tree.push_scope(parent);
     let end_ndx = start_ndx + scope_set.len();
     (start_ndx..end_ndx).into_iter().zip(scope_set.into_iter())
         .for_each(|(ndx, key)| {
-            scope_map.insert(key, ndx);
+            tree.insert(scope, key, ndx);
         });
 
-    Ok(scope_map)
+    Ok(scope)
 }
 
 /// Should only be called on __init__ functions to gather the proper class
@@ -255,16 +294,48 @@ fn unpack_assign_alias(scope: &mut HashSet<String>, target: &Expression,
     Ok(())
 }
 
-/// Traverses the compiler's scope list to find a value, if the value is found
-/// a tuple (scope_position, value_offset) is returned.
-pub fn lookup_value(scope: &Vec<HashMap<String, usize>>, id: &str)
-    -> Result<(usize, usize), CompilerError> {
-    for (ndx, tbl) in scope.iter().enumerate().rev() {
-        if let Some(offset) = tbl.get(id) {
-            return Ok((ndx, *offset))
-        }
-    }
-    Err(CompilerError::NameError(id.to_string()))
+// Value lookup now lives on `ScopeTree::resolve`, which walks parent links
+// instead of a flat back-to-front vector and can answer "what scope is node
+// N in" via `scope_for`.
+
+/// Emits a call to a runtime/protocol function (e.g. `iter`/`next` for the
+/// `for` loop desugar) and returns the operand holding its result.
+pub fn gen_call_inst(cfg: &mut CFG, block: String, func: &str,
+    args: Vec<Operand>) -> Operand {
+    let dest = cfg.new_temp();
+    cfg.add_inst(&block, Instruction::Call(
+        CallStruct::new(Some(dest.clone()), func.to_string(), args)));
+    dest
+}
+
+/// Emits an assignment of `value` into the given assignment target, shared
+/// by the `for` loop target binding and plain `Assign` statements. A bare
+/// `Name` target is resolved through `tree` first, the same way
+/// `compile_expr`'s `Name` arm resolves a read, so a write to a parameter or
+/// nested-block local that shadows an outer binding lands in the shadowing
+/// scope/offset instead of losing that distinction once it's just a string.
+/// An `Attribute` target (`self.x = ...`) resolves the same way, by its
+/// attribute name alone: `gather_class_init` is what binds `self.x` into the
+/// class scope in the first place, under the bare name `x`, so looking up
+/// `attr` through `tree` finds the same slot.
+pub fn gen_assign_inst(cfg: &mut CFG, block: String, tree: &ScopeTree,
+    scope: ScopeId, target: &Expression, value: Operand) {
+    let target = match *target {
+        Expression::Name { ref id, .. } => {
+            let (def_scope, offset) = tree.resolve(scope, id)
+                .unwrap_or_else(|e| panic!("{:?}", e));
+            Operand::Local(def_scope, offset)
+        },
+        Expression::Attribute { ref attr, .. } => {
+            let (def_scope, offset) = tree.resolve(scope, attr)
+                .unwrap_or_else(|e| panic!("{:?}", e));
+            Operand::Local(def_scope, offset)
+        },
+        _ => unimplemented!()
+    };
+
+    cfg.add_inst(&block, Instruction::Assign(
+        AssignStruct::new(target, value)));
 }
 
 lazy_static! {